@@ -16,6 +16,12 @@ pub enum SamplingError {
     #[error("The target process has probably been terminated. {0}, {1}")]
     ProcessTerminated(&'static str, KernelError),
 
+    #[error("Could not read the list of loaded libraries from the target process: {0}")]
+    DyldInfoUnavailable(KernelError),
+
+    #[error("The target process uses dyld_all_image_infos format {0}, but only the 64-bit format (1) is supported.")]
+    UnsupportedDyldInfoFormat(i32),
+
     #[error("Could not obtain root task.")]
     CouldNotObtainRootTask,
 }
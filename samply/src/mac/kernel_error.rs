@@ -299,6 +299,9 @@ pub enum KernelError {
     #[error("invalid reply port used in a STRICT_REPLY message")]
     MachRcvInvalidReply,
 
+    #[error("read cancelled by caller")]
+    Cancelled,
+
     #[error("Unknown kernel error {0}")]
     Unknown(kern_return_t),
 }
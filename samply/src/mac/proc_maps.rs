@@ -2,6 +2,8 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::mem::MaybeUninit;
 use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
 use std::{mem, ptr};
 
 use dyld_bindings::{dyld_all_image_infos, dyld_image_info};
@@ -30,10 +32,11 @@ use mach::vm_types::{mach_vm_address_t, mach_vm_size_t};
 #[cfg(target_arch = "x86_64")]
 use mach::{structs::x86_thread_state64_t, thread_status::x86_THREAD_STATE64};
 use object::macho::{
-    MachHeader64, SegmentCommand64, CPU_SUBTYPE_ARM64E, CPU_SUBTYPE_ARM64_ALL, CPU_SUBTYPE_MASK,
-    CPU_SUBTYPE_X86_64_ALL, CPU_SUBTYPE_X86_64_H, CPU_TYPE_ARM64, CPU_TYPE_X86_64, MH_EXECUTE,
+    MachHeader64, Nlist64, SegmentCommand64, CPU_SUBTYPE_ARM64E, CPU_SUBTYPE_ARM64_ALL,
+    CPU_SUBTYPE_MASK, CPU_SUBTYPE_X86_64_ALL, CPU_SUBTYPE_X86_64_H, CPU_TYPE_ARM64,
+    CPU_TYPE_X86_64, MH_DYLIB_IN_CACHE, MH_EXECUTE, N_SECT, N_STAB, N_TYPE,
 };
-use object::read::macho::{MachHeader, Section, Segment};
+use object::read::macho::{LoadCommandIterator, MachHeader, Section, Segment};
 use object::LittleEndian;
 #[cfg(target_arch = "aarch64")]
 use once_cell::sync::Lazy;
@@ -47,6 +50,10 @@ use super::kernel_error::{self, IntoResult, KernelError};
 use super::task_profiler::UnwindSectionBytes;
 
 pub const TASK_DYLD_INFO_COUNT: mach_msg_type_number_t = 5;
+/// `task_dyld_info::all_image_info_format` only says whether `dyld_all_image_infos`
+/// uses the 32-bit (`TASK_DYLD_ALL_IMAGE_INFO_32`, 0) or the 64-bit layout. Our
+/// bindings are for the 64-bit one.
+pub const TASK_DYLD_ALL_IMAGE_INFO_64: mach::vm_types::integer_t = 1;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DyldInfo {
@@ -59,6 +66,48 @@ pub struct DyldInfo {
     pub code_id: Option<CodeId>,
     pub arch: Option<&'static str>,
     pub unwind_sections: UnwindSectionInfo,
+    pub segments: Vec<SegmentInfo>,
+    pub source: ImageSource,
+}
+
+/// One `LC_SEGMENT_64` of a loaded image. Add the image's slide, i.e.
+/// `base_avma - module_info.base_svma`, to get the segment's AVMA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub name: String,
+    pub svma: u64,
+    pub vmsize: u64,
+    pub fileoff: u64,
+}
+
+/// Where the bytes of a loaded image come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSource {
+    /// The image was mapped from its own file on disk.
+    OnDisk,
+    /// The image is part of the dyld shared cache. On macOS 11+, there is no
+    /// separate file on disk for such images, so symbols and unwind info have
+    /// to come from the shared cache.
+    SharedCache { cache_uuid: Option<Uuid> },
+}
+
+/// Finds the image whose `base_avma..base_avma + vmsize` range contains `addr`.
+///
+/// `images` must be sorted by `base_avma` and must not overlap.
+#[allow(unused)]
+pub fn find_image_for_address(images: &[DyldInfo], addr: u64) -> Option<&DyldInfo> {
+    let index = images
+        .binary_search_by(|image| {
+            if image.base_avma > addr {
+                Ordering::Greater
+            } else if addr - image.base_avma >= image.vmsize {
+                Ordering::Less
+            } else {
+                Ordering::Equal
+            }
+        })
+        .ok()?;
+    Some(&images[index])
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -84,6 +133,11 @@ pub struct UnwindSectionInfo {
     pub text_segment: Option<(u64, u64)>,
 }
 
+/// How much memory `DyldInfoManager` keeps mapped from its task. This mostly
+/// holds the headers and load commands of all images, which are only needed
+/// while the image list is re-enumerated, plus `dyld_all_image_infos` itself.
+const DYLD_INFO_CACHE_BUDGET: u64 = 32 * 1024 * 1024;
+
 pub struct DyldInfoManager {
     task: mach_port_t,
     memory: ForeignMemory,
@@ -94,9 +148,11 @@ pub struct DyldInfoManager {
 
 impl DyldInfoManager {
     pub fn new(task: mach_port_t) -> DyldInfoManager {
+        let mut memory = ForeignMemory::new(task);
+        memory.set_cache_budget(DYLD_INFO_CACHE_BUDGET);
         DyldInfoManager {
             task,
-            memory: ForeignMemory::new(task),
+            memory,
             all_image_info_addr: None,
             last_change_timestamp: None,
             saved_image_info: Vec::new(),
@@ -107,13 +163,49 @@ impl DyldInfoManager {
         self.memory.clear();
     }
 
-    pub fn check_for_changes(&mut self) -> kernel_error::Result<Vec<Modification<DyldInfo>>> {
+    pub fn check_for_changes(&mut self) -> Result<Vec<Modification<DyldInfo>>, SamplingError> {
+        self.check_for_changes_impl(true)
+    }
+
+    /// Like `check_for_changes`, but for callers which already hold the task
+    /// suspended, e.g. around a batch of operations on the task.
+    ///
+    /// This does not call `task_suspend` / `task_resume`, so it leaves the task's
+    /// suspend count untouched. The caller must keep the task suspended for the
+    /// entire call; otherwise dyld can modify the image list while we read it.
+    #[allow(unused)]
+    pub fn check_for_changes_with_suspended_task(
+        &mut self,
+    ) -> Result<Vec<Modification<DyldInfo>>, SamplingError> {
+        self.check_for_changes_impl(false)
+    }
+
+    fn check_for_changes_impl(
+        &mut self,
+        suspend_task: bool,
+    ) -> Result<Vec<Modification<DyldInfo>>, SamplingError> {
+        let info_addr = match self.all_image_info_addr {
+            Some(addr) => addr,
+            None => {
+                let addr = get_all_image_info_addr(self.task)?;
+                self.all_image_info_addr = Some(addr);
+                addr
+            }
+        };
+
+        self.check_for_changes_at(info_addr, suspend_task)
+            .map_err(|err| SamplingError::Ignorable("Could not check process libraries", err))
+    }
+
+    fn check_for_changes_at(
+        &mut self,
+        info_addr: u64,
+        suspend_task: bool,
+    ) -> kernel_error::Result<Vec<Modification<DyldInfo>>> {
         // Avoid suspending the task if we know that the image info array hasn't changed.
         // The process-wide dyld_all_image_infos instance always stays in the same place,
         // so we can keep its memory mapped and just check the timestamp in the mapped memory.
-        if let (Some(last_change_timestamp), Some(info_addr)) =
-            (self.last_change_timestamp, self.all_image_info_addr)
-        {
+        if let Some(last_change_timestamp) = self.last_change_timestamp {
             let image_infos: &dyld_all_image_infos =
                 unsafe { self.memory.get_type_ref_at_address(info_addr) }?;
             // infoArrayChangeTimestamp is 10.12+. TODO: check version
@@ -122,64 +214,97 @@ impl DyldInfoManager {
             }
         }
 
-        // Now, suspend the task, enumerate the libraries, and diff against our saved list.
-        with_suspended_task(self.task, || {
-            let info_addr = match self.all_image_info_addr {
-                Some(addr) => addr,
-                None => get_all_image_info_addr(self.task)?,
-            };
+        // Now, suspend the task (unless the caller already did), enumerate the
+        // libraries, and diff against our saved list.
+        if suspend_task {
+            with_suspended_task(self.task, || self.read_image_list_changes(info_addr))
+        } else {
+            self.read_image_list_changes(info_addr)
+        }
+    }
 
-            self.all_image_info_addr = Some(info_addr);
-
-            let (
-                info_array_addr,
-                info_array_count,
-                info_array_change_timestamp,
-                dyld_image_load_addr,
-                dyld_image_path,
-            ) = {
-                let image_infos: &dyld_all_image_infos =
-                    unsafe { self.memory.get_type_ref_at_address(info_addr) }?;
-                (
-                    image_infos.infoArray as usize as u64,
-                    image_infos.infoArrayCount,
-                    image_infos.infoArrayChangeTimestamp, // 10.12+
-                    image_infos.dyldImageLoadAddress as usize as u64,
-                    image_infos.dyldPath as usize as u64, // 10.12+
-                )
+    /// The task must be suspended during this call.
+    fn read_image_list_changes(
+        &mut self,
+        info_addr: u64,
+    ) -> kernel_error::Result<Vec<Modification<DyldInfo>>> {
+        let (
+            info_array_addr,
+            info_array_count,
+            info_array_change_timestamp,
+            dyld_image_load_addr,
+            dyld_image_path,
+            shared_cache_uuid,
+        ) = {
+            let image_infos: &dyld_all_image_infos =
+                unsafe { self.memory.get_type_ref_at_address(info_addr) }?;
+            let shared_cache_uuid = match image_infos.sharedCacheBaseAddress {
+                0 => None,
+                _ => Some(Uuid::from_bytes(image_infos.sharedCacheUUID)),
             };
+            (
+                image_infos.infoArray as usize as u64,
+                image_infos.infoArrayCount,
+                image_infos.infoArrayChangeTimestamp, // 10.12+
+                image_infos.dyldImageLoadAddress as usize as u64,
+                image_infos.dyldPath as usize as u64, // 10.12+
+                shared_cache_uuid,
+            )
+        };
 
-            // From dyld_images.h:
-            // For a snashot of what images are currently loaded, the infoArray fields contain a pointer
-            // to an array of all images. If infoArray is NULL, it means it is being modified, come back later.
-            if info_array_addr == 0 {
-                // Pretend there are no modifications. We will pick up the modifications the next time we're called.
-                return Ok(Vec::new());
-            }
+        // From dyld_images.h:
+        // For a snashot of what images are currently loaded, the infoArray fields contain a pointer
+        // to an array of all images. If infoArray is NULL, it means it is being modified, come back later.
+        if info_array_addr == 0 {
+            // Pretend there are no modifications. We will pick up the modifications the next time we're called.
+            return Ok(Vec::new());
+        }
 
-            let new_image_info = enumerate_dyld_images(
-                &mut self.memory,
-                info_array_addr,
-                info_array_count,
-                dyld_image_load_addr,
-                dyld_image_path,
-            )?;
+        let new_image_info = enumerate_dyld_images(
+            &mut self.memory,
+            info_array_addr,
+            info_array_count,
+            dyld_image_load_addr,
+            dyld_image_path,
+            shared_cache_uuid,
+        )?;
 
-            // self.saved_image_info and new_image_info are sorted by address. Diff the two lists.
-            let diff =
-                diff_sorted_slices(&self.saved_image_info, &new_image_info, |left, right| {
-                    left.base_avma.cmp(&right.base_avma)
-                });
+        // self.saved_image_info and new_image_info are sorted by address. Diff the two lists.
+        let diff = diff_sorted_slices(&self.saved_image_info, &new_image_info, |left, right| {
+            left.base_avma.cmp(&right.base_avma)
+        });
 
-            self.last_change_timestamp = Some(info_array_change_timestamp);
-            self.saved_image_info = new_image_info;
+        self.last_change_timestamp = Some(info_array_change_timestamp);
+        self.saved_image_info = new_image_info;
 
-            Ok(diff)
-        })
+        Ok(diff)
+    }
+}
+
+/// Returns the address of the task's `dyld_all_image_infos`, checking that it
+/// uses the layout of our bindings.
+fn get_all_image_info_addr(task: mach_port_t) -> Result<u64, SamplingError> {
+    let dyld_info = get_task_dyld_info(task).map_err(|err| match err {
+        KernelError::InvalidArgument
+        | KernelError::MachSendInvalidDest
+        | KernelError::Terminated => {
+            SamplingError::ProcessTerminated("task_info in get_task_dyld_info", err)
+        }
+        KernelError::InvalidTask
+        | KernelError::InvalidRight
+        | KernelError::NoAccess
+        | KernelError::ProtectionFailure => SamplingError::DyldInfoUnavailable(err),
+        err => SamplingError::Ignorable("task_info in get_task_dyld_info", err),
+    })?;
+    if dyld_info.all_image_info_format != TASK_DYLD_ALL_IMAGE_INFO_64 {
+        return Err(SamplingError::UnsupportedDyldInfoFormat(
+            dyld_info.all_image_info_format,
+        ));
     }
+    Ok(dyld_info.all_image_info_addr)
 }
 
-fn get_all_image_info_addr(task: mach_port_t) -> kernel_error::Result<u64> {
+fn get_task_dyld_info(task: mach_port_t) -> kernel_error::Result<task_dyld_info> {
     let mut dyld_info = task_dyld_info {
         all_image_info_addr: 0,
         all_image_info_size: 0,
@@ -197,17 +322,90 @@ fn get_all_image_info_addr(task: mach_port_t) -> kernel_error::Result<u64> {
     }
     .into_result()?;
 
-    Ok(dyld_info.all_image_info_addr)
+    Ok(dyld_info)
+}
+
+/// Keeps a task suspended for as long as it's alive. Resuming happens in `Drop`,
+/// so that a panic or an early return can't leave the target process frozen.
+struct TaskSuspension(mach_port_t);
+
+impl TaskSuspension {
+    fn new(task: mach_port_t) -> kernel_error::Result<Self> {
+        unsafe { task_suspend(task) }.into_result()?;
+        Ok(Self(task))
+    }
+}
+
+impl Drop for TaskSuspension {
+    fn drop(&mut self) {
+        let _ = unsafe { task_resume(self.0) };
+    }
 }
 
 fn with_suspended_task<T>(
     task: mach_port_t,
     f: impl FnOnce() -> kernel_error::Result<T>,
 ) -> kernel_error::Result<T> {
-    unsafe { task_suspend(task) }.into_result()?;
-    let result = f();
-    let _ = unsafe { task_resume(task) };
-    result
+    let _suspension = TaskSuspension::new(task)?;
+    f()
+}
+
+/// Copies a `T` out of the task with `mach_vm_read_overwrite`, for data which
+/// shouldn't be read through a long-lived `ForeignMemory` mapping.
+///
+/// The caller must make sure that the bytes at `address` form a valid `T`, e.g.
+/// because `T` is a `#[repr(C)]` struct that dyld or the kernel wrote there.
+unsafe fn copy_from_task<T>(task: mach_port_t, address: u64) -> kernel_error::Result<T> {
+    let mut value: MaybeUninit<T> = MaybeUninit::uninit();
+    let expected_size = mem::size_of::<T>() as mach_vm_size_t;
+    let mut size = expected_size;
+    mach_vm_read_overwrite(
+        task,
+        address,
+        size,
+        value.as_mut_ptr() as mach_vm_address_t,
+        &mut size,
+    )
+    .into_result()?;
+    if size != expected_size {
+        return Err(KernelError::InvalidValue);
+    }
+    Ok(value.assume_init())
+}
+
+/// Copies the `dyld_image_info` array out of the task with a single
+/// `mach_vm_read_overwrite`. dyld reallocates this array as images are loaded,
+/// so unlike `dyld_all_image_infos`, it must not be read through the long-lived
+/// `ForeignMemory` mappings of `DyldInfoManager`: those could keep pointing at
+/// the old allocation and give us a stale image list.
+fn read_image_info_array(
+    task: mach_port_t,
+    info_array_addr: u64,
+    info_array_count: usize,
+) -> kernel_error::Result<Vec<dyld_image_info>> {
+    if info_array_count == 0 {
+        return Ok(Vec::new());
+    }
+    let expected_size = info_array_count
+        .checked_mul(mem::size_of::<dyld_image_info>())
+        .ok_or(KernelError::InvalidValue)? as mach_vm_size_t;
+    let mut image_infos: Vec<dyld_image_info> = Vec::with_capacity(info_array_count);
+    let mut size = expected_size;
+    unsafe {
+        mach_vm_read_overwrite(
+            task,
+            info_array_addr,
+            size,
+            image_infos.as_mut_ptr() as mach_vm_address_t,
+            &mut size,
+        )
+    }
+    .into_result()?;
+    if size != expected_size {
+        return Err(KernelError::InvalidValue);
+    }
+    unsafe { image_infos.set_len(info_array_count) };
+    Ok(image_infos)
 }
 
 fn enumerate_dyld_images(
@@ -216,42 +414,28 @@ fn enumerate_dyld_images(
     info_array_count: u32,
     dyld_image_load_addr: u64,
     dyld_image_path: u64,
+    shared_cache_uuid: Option<Uuid>,
 ) -> kernel_error::Result<Vec<DyldInfo>> {
     // Adapted from rbspy and from the Gecko profiler's shared-libraries-macos.cc.
-    let mut vec = vec![get_dyld_image_info(
+    // get_dyld_image_info returns None for images without a 64-bit Mach-O
+    // header. Those are skipped, so that one odd image doesn't cost us the rest.
+    let mut vec = Vec::new();
+    vec.extend(get_dyld_image_info(
         memory,
         dyld_image_load_addr,
         dyld_image_path,
-    )?];
-
-    for image_index in 0..info_array_count {
-        let (base_avma, image_file_path) = {
-            let info_array_elem_addr = info_array_addr
-                + image_index as u64 * mem::size_of::<dyld_image_info>() as mach_vm_address_t;
-            let mut image_info: MaybeUninit<dyld_image_info> = MaybeUninit::uninit();
-            let mut size = mem::size_of::<dyld_image_info>() as mach_vm_size_t;
-            unsafe {
-                mach_vm_read_overwrite(
-                    memory.task,
-                    info_array_elem_addr,
-                    size,
-                    &mut image_info as *mut MaybeUninit<dyld_image_info> as mach_vm_address_t,
-                    &mut size,
-                )
-                .into_result()?;
-            }
-
-            if size != mem::size_of::<dyld_image_info>() as mach_vm_size_t {
-                return Err(kernel_error::KernelError::InvalidValue);
-            }
-
-            let image_info = unsafe { image_info.assume_init() };
-            (
-                image_info.imageLoadAddress as usize as u64,
-                image_info.imageFilePath as usize as u64,
-            )
-        };
-        vec.push(get_dyld_image_info(memory, base_avma, image_file_path)?);
+        shared_cache_uuid,
+    )?);
+
+    let image_infos =
+        read_image_info_array(memory.task, info_array_addr, info_array_count as usize)?;
+    for image_info in image_infos {
+        vec.extend(get_dyld_image_info(
+            memory,
+            image_info.imageLoadAddress as usize as u64,
+            image_info.imageFilePath as usize as u64,
+            shared_cache_uuid,
+        )?);
     }
     vec.sort_by_key(|info| info.base_avma);
     Ok(vec)
@@ -268,65 +452,126 @@ fn get_arch_string(cputype: u32, cpusubtype: u32) -> Option<&'static str> {
     Some(s)
 }
 
-fn get_dyld_image_info(
-    memory: &mut ForeignMemory,
-    base_avma: u64,
-    image_file_path: u64,
-) -> kernel_error::Result<DyldInfo> {
-    let filename = {
-        let mut filename_bytes: MaybeUninit<[i8; 512]> = MaybeUninit::uninit();
-        let mut size = 512;
+/// The CPU type of a task, as found in the Mach-O header of its main executable.
+///
+/// For a process which runs under Rosetta, this is the translated architecture
+/// (x86_64), not the architecture of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuType {
+    pub cputype: u32,
+    pub cpusubtype: u32,
+}
 
-        unsafe {
-            mach_vm_read_overwrite(
-                memory.task,
-                image_file_path,
-                size as mach_vm_size_t,
-                &mut filename_bytes as *mut MaybeUninit<[i8; 512]> as mach_vm_address_t,
-                &mut size,
-            )
-            .into_result()?;
-        }
-        let filename_bytes = unsafe { filename_bytes.assume_init() };
+impl CpuType {
+    pub fn arch(&self) -> Option<&'static str> {
+        get_arch_string(self.cputype, self.cpusubtype)
+    }
 
-        unsafe { std::ffi::CStr::from_ptr(filename_bytes.as_ptr()) }
-            .to_string_lossy()
-            .to_string()
-    };
+    /// Whether this matches the architecture samply was built for. We only read
+    /// the thread state flavor of our own architecture.
+    pub fn is_native(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        let native_cputype = CPU_TYPE_X86_64;
+        #[cfg(target_arch = "aarch64")]
+        let native_cputype = CPU_TYPE_ARM64;
+        self.cputype == native_cputype
+    }
+}
 
-    let header = {
-        let mut header: MaybeUninit<MachHeader64<LittleEndian>> = MaybeUninit::uninit();
-        let mut size = mem::size_of::<MachHeader64<LittleEndian>>() as mach_vm_size_t;
+/// Reads the CPU type of `task` from the header of its main executable.
+pub fn get_task_cpu_type(task: mach_port_t) -> Result<CpuType, SamplingError> {
+    let info_addr = get_all_image_info_addr(task)?;
+    read_main_executable_cpu_type(task, info_addr)
+        .map_err(|err| SamplingError::Ignorable("Could not read the executable's CPU type", err))
+}
 
-        unsafe {
-            mach_vm_read_overwrite(
-                memory.task,
-                base_avma,
-                size,
-                &mut header as *mut MaybeUninit<MachHeader64<LittleEndian>> as mach_vm_address_t,
-                &mut size,
-            )
-            .into_result()?;
-        }
+fn read_main_executable_cpu_type(
+    task: mach_port_t,
+    info_addr: u64,
+) -> kernel_error::Result<CpuType> {
+    // Keep dyld from reallocating infoArray while we read it.
+    let _suspension = TaskSuspension::new(task)?;
+    let image_infos: dyld_all_image_infos = unsafe { copy_from_task(task, info_addr) }?;
+    let info_array_addr = image_infos.infoArray as usize as u64;
+    if info_array_addr == 0 || image_infos.infoArrayCount == 0 {
+        // dyld is in the middle of modifying the list, or hasn't populated it yet.
+        return Err(KernelError::InvalidValue);
+    }
 
-        unsafe { header.assume_init() }
-    };
+    // dyld lists the main executable first.
+    let main_executable = read_image_info_array(task, info_array_addr, 1)?[0];
+    let header: MachHeader64<LittleEndian> =
+        unsafe { copy_from_task(task, main_executable.imageLoadAddress as usize as u64) }?;
+    let endian = LittleEndian;
+    if !header.is_little_endian() || header.filetype(endian) != MH_EXECUTE {
+        return Err(KernelError::InvalidValue);
+    }
+    Ok(CpuType {
+        cputype: header.cputype(endian),
+        cpusubtype: header.cpusubtype(endian),
+    })
+}
+
+/// Reads the Mach-O header of the image at `base_avma` and returns it together
+/// with an iterator over its load commands.
+///
+/// Everything we parse assumes 64-bit little-endian Mach-O layouts. On current
+/// macOS, 32-bit processes can't run at all, so we don't parse the 32-bit
+/// layouts. Rather than misparsing the load commands of such an image, this
+/// returns `None` for any header that doesn't have the 64-bit magic.
+fn read_header_and_load_commands(
+    memory: &mut ForeignMemory,
+    base_avma: u64,
+) -> kernel_error::Result<
+    Option<(
+        MachHeader64<LittleEndian>,
+        LoadCommandIterator<'_, LittleEndian>,
+    )>,
+> {
+    let header: MachHeader64<LittleEndian> = unsafe { copy_from_task(memory.task, base_avma) }?;
+
+    if !header.is_little_endian() {
+        return Ok(None);
+    }
 
     let endian = LittleEndian;
     let commands_start = base_avma + mem::size_of::<MachHeader64<LittleEndian>>() as u64;
     let commands_end = commands_start + header.sizeofcmds(endian) as u64;
     let header_and_command_data = memory.get_slice(base_avma..commands_end)?;
-    let mut load_commands = header
+    let load_commands = header
         .load_commands(endian, header_and_command_data, 0)
-        .map_err(|_| kernel_error::KernelError::InvalidValue)?;
+        .map_err(|_| KernelError::InvalidValue)?;
+    Ok(Some((header, load_commands)))
+}
+
+fn get_dyld_image_info(
+    memory: &mut ForeignMemory,
+    base_avma: u64,
+    image_file_path: u64,
+    shared_cache_uuid: Option<Uuid>,
+) -> kernel_error::Result<Option<DyldInfo>> {
+    let filename = memory.read_c_string(image_file_path, libc::PATH_MAX as usize)?;
+
+    let endian = LittleEndian;
+    let Some((header, mut load_commands)) = read_header_and_load_commands(memory, base_avma)?
+    else {
+        return Ok(None);
+    };
 
     let mut base_svma = 0;
     let mut vmsize: u64 = 0;
     let mut uuid = None;
     let mut sections = HashMap::new();
+    let mut segments = Vec::new();
 
     while let Ok(Some(command)) = load_commands.next() {
         if let Ok(Some((segment, section_data))) = SegmentCommand64::from_command(command) {
+            segments.push(SegmentInfo {
+                name: String::from_utf8_lossy(segment.name()).into_owned(),
+                svma: segment.vmaddr(endian),
+                vmsize: segment.vmsize(endian),
+                fileoff: segment.fileoff(endian),
+            });
             if segment.name() == b"__TEXT" {
                 base_svma = segment.vmaddr(endian);
                 vmsize = segment.vmsize(endian);
@@ -349,7 +594,7 @@ fn get_dyld_image_info(
         sections.get(name).map(|(addr, size)| *addr..*addr + *size)
     };
 
-    Ok(DyldInfo {
+    Ok(Some(DyldInfo {
         file: filename,
         base_avma,
         vmsize,
@@ -372,7 +617,112 @@ fn get_dyld_image_info(
             eh_frame_section: sections.get(&b"__eh_frame"[..]).cloned(),
             text_segment: Some((base_svma, vmsize)),
         },
-    })
+        segments,
+        source: if header.flags(endian) & MH_DYLIB_IN_CACHE != 0 {
+            ImageSource::SharedCache {
+                cache_uuid: shared_cache_uuid,
+            }
+        } else {
+            ImageSource::OnDisk
+        },
+    }))
+}
+
+/// Reads the symbol table of a loaded image directly from the target task's
+/// memory, by following the image's `LC_SYMTAB` command into its `__LINKEDIT`
+/// segment. This works even if the image's file on disk is no longer available.
+///
+/// Returns `(name, avma)` pairs for all symbols that are defined in a section.
+#[allow(unused)]
+pub fn get_symbols_from_memory(
+    memory: &mut ForeignMemory,
+    image: &DyldInfo,
+) -> kernel_error::Result<Vec<(String, u64)>> {
+    let endian = LittleEndian;
+    let (_, mut load_commands) =
+        read_header_and_load_commands(memory, image.base_avma)?.ok_or(KernelError::InvalidValue)?;
+
+    let mut symtab = None;
+    let mut undefined_symbols_start = None;
+    while let Ok(Some(command)) = load_commands.next() {
+        if let Ok(Some(symtab_command)) = command.symtab() {
+            symtab = Some((
+                symtab_command.symoff.get(endian) as u64,
+                symtab_command.nsyms.get(endian) as u64,
+                symtab_command.stroff.get(endian) as u64,
+                symtab_command.strsize.get(endian) as u64,
+            ));
+        } else if let Ok(Some(dysymtab_command)) = command.dysymtab() {
+            undefined_symbols_start = Some(dysymtab_command.iundefsym.get(endian) as u64);
+        }
+    }
+
+    let linkedit = image
+        .segments
+        .iter()
+        .find(|segment| segment.name == "__LINKEDIT");
+    let (Some(linkedit), Some((symoff, mut nsyms, stroff, strsize))) = (linkedit, symtab) else {
+        return Ok(Vec::new());
+    };
+    // LC_DYSYMTAB groups the symbol table into local, externally defined, and
+    // undefined symbols, in that order. Undefined symbols would be filtered out
+    // below anyway, so don't map them at all.
+    if let Some(undefined_symbols_start) = undefined_symbols_start {
+        nsyms = nsyms.min(undefined_symbols_start);
+    }
+    // An empty symbol table usually has symoff == 0, and an empty string table can
+    // have stroff == 0, which would be outside of __LINKEDIT. Without symbols or
+    // without any names for them, there's nothing to return anyway.
+    if nsyms == 0 || strsize == 0 {
+        return Ok(Vec::new());
+    }
+
+    // The symbol and string tables are given as file offsets inside __LINKEDIT.
+    // These values come from the target's memory, so check that the tables
+    // actually lie within the segment before mapping them.
+    let slide = image.base_avma.wrapping_sub(image.module_info.base_svma);
+    let linkedit_avma = linkedit.svma.wrapping_add(slide);
+    let linkedit_avma_range = |fileoff: u64, size: u64| -> kernel_error::Result<Range<u64>> {
+        let start_offset = fileoff
+            .checked_sub(linkedit.fileoff)
+            .ok_or(KernelError::InvalidValue)?;
+        let end_offset = start_offset
+            .checked_add(size)
+            .filter(|end_offset| *end_offset <= linkedit.vmsize)
+            .ok_or(KernelError::InvalidValue)?;
+        let start = linkedit_avma
+            .checked_add(start_offset)
+            .ok_or(KernelError::InvalidValue)?;
+        let end = linkedit_avma
+            .checked_add(end_offset)
+            .ok_or(KernelError::InvalidValue)?;
+        Ok(start..end)
+    };
+
+    let symbols_size = nsyms * mem::size_of::<Nlist64<LittleEndian>>() as u64;
+    let raw_symbols: Vec<(u32, u64)> = {
+        let symbol_data = memory.get_slice(linkedit_avma_range(symoff, symbols_size)?)?;
+        let (nlists, _) =
+            object::pod::slice_from_bytes::<Nlist64<LittleEndian>>(symbol_data, nsyms as usize)
+                .map_err(|_| KernelError::InvalidValue)?;
+        nlists
+            .iter()
+            .filter(|nlist| nlist.n_type & N_STAB == 0 && nlist.n_type & N_TYPE == N_SECT)
+            .map(|nlist| (nlist.n_strx.get(endian), nlist.n_value.get(endian)))
+            .collect()
+    };
+
+    let string_data = memory.get_slice(linkedit_avma_range(stroff, strsize)?)?;
+    let symbols = raw_symbols
+        .into_iter()
+        .filter_map(|(strx, svma)| {
+            let name_bytes = string_data.get(strx as usize..)?;
+            let name_len = name_bytes.iter().position(|b| *b == 0)?;
+            let name = String::from_utf8_lossy(&name_bytes[..name_len]).into_owned();
+            Some((name, svma.wrapping_add(slide)))
+        })
+        .collect();
+    Ok(symbols)
 }
 
 // bindgen seemed to put all the members for this struct as a single opaque blob:
@@ -411,10 +761,43 @@ impl arm_thread_state64_t {
 #[cfg(target_arch = "aarch64")]
 pub static ARM_THREAD_STATE64: thread_state_flavor_t = 6;
 
+/// The names of the registers in `ThreadRegisters::gprs`, in the same order.
 #[cfg(target_arch = "x86_64")]
-fn get_unwinding_registers(
-    thread_act: mach_port_t,
-) -> kernel_error::Result<(u64, UnwindRegsX86_64)> {
+#[allow(unused)]
+pub const THREAD_GPR_NAMES: [&str; 16] = [
+    "rax", "rbx", "rcx", "rdx", "rdi", "rsi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+/// The names of the registers in `ThreadRegisters::gprs`, in the same order.
+#[cfg(target_arch = "aarch64")]
+#[allow(unused)]
+pub const THREAD_GPR_NAMES: [&str; 32] = [
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13", "x14",
+    "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27",
+    "x28", "fp", "lr", "sp",
+];
+
+/// The general-purpose registers of a thread, as returned by `thread_get_state`.
+///
+/// `pc`, `sp` and `fp` are duplicated out of `gprs` (or, for `pc`, out of the
+/// thread state) so that callers don't need to know the architecture's layout.
+/// On aarch64, `pc` and `lr` may still carry pointer authentication bits.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadRegisters {
+    pub pc: u64,
+    pub sp: u64,
+    pub fp: u64,
+    #[cfg(target_arch = "aarch64")]
+    pub lr: u64,
+    /// rflags on x86_64, cpsr on aarch64.
+    pub flags: u64,
+    pub gprs: [u64; THREAD_GPR_NAMES.len()],
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn get_thread_registers(thread_act: mach_port_t) -> kernel_error::Result<ThreadRegisters> {
     let mut state: x86_thread_state64_t = unsafe { mem::zeroed() };
     let mut count = x86_thread_state64_t::count();
     unsafe {
@@ -426,10 +809,66 @@ fn get_unwinding_registers(
         )
     }
     .into_result()?;
-    Ok((
-        state.__rip,
-        UnwindRegsX86_64::new(state.__rip, state.__rsp, state.__rbp),
-    ))
+    Ok(ThreadRegisters {
+        pc: state.__rip,
+        sp: state.__rsp,
+        fp: state.__rbp,
+        flags: state.__rflags,
+        gprs: [
+            state.__rax,
+            state.__rbx,
+            state.__rcx,
+            state.__rdx,
+            state.__rdi,
+            state.__rsi,
+            state.__rbp,
+            state.__rsp,
+            state.__r8,
+            state.__r9,
+            state.__r10,
+            state.__r11,
+            state.__r12,
+            state.__r13,
+            state.__r14,
+            state.__r15,
+        ],
+    })
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn get_thread_registers(thread_act: mach_port_t) -> kernel_error::Result<ThreadRegisters> {
+    let mut state: arm_thread_state64_t = unsafe { mem::zeroed() };
+    let mut count = arm_thread_state64_t::count();
+    unsafe {
+        thread_get_state(
+            thread_act,
+            ARM_THREAD_STATE64,
+            &mut state as *mut _ as thread_state_t,
+            &mut count as *mut _,
+        )
+    }
+    .into_result()?;
+    let mut gprs = [0; THREAD_GPR_NAMES.len()];
+    gprs[..29].copy_from_slice(&state.__x);
+    gprs[29] = state.__fp;
+    gprs[30] = state.__lr;
+    gprs[31] = state.__sp;
+    Ok(ThreadRegisters {
+        pc: state.__pc,
+        sp: state.__sp,
+        fp: state.__fp,
+        lr: state.__lr,
+        flags: state.__cpsr as u64,
+        gprs,
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn get_unwinding_registers(
+    thread_act: mach_port_t,
+) -> kernel_error::Result<(u64, UnwindRegsX86_64)> {
+    let regs = get_thread_registers(thread_act)?;
+    Ok((regs.pc, UnwindRegsX86_64::new(regs.pc, regs.sp, regs.fp)))
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -511,32 +950,36 @@ static PTR_AUTH_MASK: Lazy<PtrAuthMask> = Lazy::new(|| {
 fn get_unwinding_registers(
     thread_act: mach_port_t,
 ) -> kernel_error::Result<(u64, UnwindRegsAarch64)> {
-    let mut state: arm_thread_state64_t = unsafe { mem::zeroed() };
-    let mut count = arm_thread_state64_t::count();
-    unsafe {
-        thread_get_state(
-            thread_act,
-            ARM_THREAD_STATE64,
-            &mut state as *mut _ as thread_state_t,
-            &mut count as *mut _,
-        )
-    }
-    .into_result()?;
+    let regs = get_thread_registers(thread_act)?;
     let mask = *PTR_AUTH_MASK;
     Ok((
-        mask.strip_ptr_auth(state.__pc),
-        UnwindRegsAarch64::new_with_ptr_auth_mask(mask, state.__lr, state.__sp, state.__fp),
+        mask.strip_ptr_auth(regs.pc),
+        UnwindRegsAarch64::new_with_ptr_auth_mask(mask, regs.lr, regs.sp, regs.fp),
     ))
 }
 
+/// The thread equivalent of `TaskSuspension`.
+struct ThreadSuspension(mach_port_t);
+
+impl ThreadSuspension {
+    fn new(thread_act: mach_port_t) -> kernel_error::Result<Self> {
+        unsafe { thread_suspend(thread_act) }.into_result()?;
+        Ok(Self(thread_act))
+    }
+}
+
+impl Drop for ThreadSuspension {
+    fn drop(&mut self) {
+        let _ = unsafe { thread_resume(self.0) };
+    }
+}
+
 fn with_suspended_thread<R>(
     thread_act: mach_port_t,
     f: impl FnOnce() -> R,
 ) -> kernel_error::Result<R> {
-    unsafe { thread_suspend(thread_act) }.into_result()?;
-    let result = f();
-    let _ = unsafe { thread_resume(thread_act) };
-    Ok(result)
+    let _suspension = ThreadSuspension::new(thread_act)?;
+    Ok(f())
 }
 
 pub struct StackwalkerRef<'a> {
@@ -637,7 +1080,16 @@ fn do_stackwalk(
 #[derive(Debug)]
 pub struct ForeignMemory {
     task: mach_port_t,
-    data: Vec<VmData>,
+    data: Vec<CachedVmData>,
+    cancellation_flag: Option<Arc<AtomicBool>>,
+    cache_budget: Option<u64>,
+    use_counter: u64,
+}
+
+#[derive(Debug)]
+struct CachedVmData {
+    vm_data: VmData,
+    last_use: u64,
 }
 
 impl ForeignMemory {
@@ -645,7 +1097,70 @@ impl ForeignMemory {
         Self {
             task,
             data: Vec::new(),
+            cancellation_flag: None,
+            cache_budget: None,
+            use_counter: 0,
+        }
+    }
+
+    /// Once `flag` is set, any read which would need to map new memory from the
+    /// task fails with `KernelError::Cancelled`. Reads from already-mapped memory
+    /// keep working.
+    ///
+    /// Nothing in the sampler sets a flag yet. Shutdown requests are handled by
+    /// the sampling thread itself, between samples, so there is no other thread
+    /// which could cancel a stack walk while it is in progress.
+    #[allow(unused)]
+    pub fn set_cancellation_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.cancellation_flag = Some(flag);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation_flag
+            .as_ref()
+            .is_some_and(|flag| flag.load(AtomicOrdering::Relaxed))
+    }
+
+    /// Limits the total size of the memory that is kept mapped from the task.
+    /// Once a new mapping pushes the total over `bytes`, the least-recently-used
+    /// mappings are unmapped. The most recently used mapping is always kept, even
+    /// if it's larger than the budget by itself.
+    pub fn set_cache_budget(&mut self, bytes: u64) {
+        self.cache_budget = Some(bytes);
+        let most_recent_index = self
+            .data
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, d)| d.last_use)
+            .map(|(i, _)| i);
+        if let Some(index) = most_recent_index {
+            self.evict_over_budget(index);
+        }
+    }
+
+    /// Unmaps least-recently-used data until the mapped size fits the cache budget.
+    /// The data at `keep_index` is never evicted; its new index is returned.
+    fn evict_over_budget(&mut self, mut keep_index: usize) -> usize {
+        let Some(budget) = self.cache_budget else {
+            return keep_index;
+        };
+        let mut mapped_size: u64 = self.data.iter().map(|d| d.vm_data.data_size as u64).sum();
+        while mapped_size > budget && self.data.len() > 1 {
+            let (lru_index, _) = self
+                .data
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != keep_index)
+                .min_by_key(|(_, d)| d.last_use)
+                .unwrap();
+            // Dropping the VmData unmaps it.
+            let evicted = self.data.remove(lru_index);
+            mapped_size -= evicted.vm_data.data_size as u64;
+            if lru_index < keep_index {
+                keep_index -= 1;
+            }
         }
+        keep_index
     }
 
     pub fn clear(&mut self) {
@@ -660,9 +1175,9 @@ impl ForeignMemory {
 
     fn data_index_for_address(&self, address: u64) -> std::result::Result<usize, usize> {
         self.data.binary_search_by(|d| {
-            if d.address_range.start > address {
+            if d.vm_data.address_range.start > address {
                 Ordering::Greater
-            } else if d.address_range.end <= address {
+            } else if d.vm_data.address_range.end <= address {
                 Ordering::Less
             } else {
                 Ordering::Equal
@@ -676,22 +1191,32 @@ impl ForeignMemory {
     ) -> kernel_error::Result<&VmData> {
         let first_byte_addr = address_range.start;
         let last_byte_addr = address_range.end - 1;
-        let vm_data = match (
+        self.use_counter += 1;
+        let index = match (
             self.data_index_for_address(first_byte_addr),
             self.data_index_for_address(last_byte_addr),
         ) {
-            (Ok(i), Ok(j)) if i == j => &self.data[i],
+            (Ok(i), Ok(j)) if i == j => i,
             (Ok(i), Ok(j)) | (Ok(i), Err(j)) | (Err(i), Ok(j)) | (Err(i), Err(j)) => {
+                if self.is_cancelled() {
+                    return Err(KernelError::Cancelled);
+                }
                 let start_addr = unsafe { mach_vm_trunc_page(first_byte_addr) };
                 let end_addr = unsafe { mach_vm_trunc_page(last_byte_addr) + vm_page_size as u64 };
                 let size = end_addr - start_addr;
-                let data = VmData::map_from_task(self.task, start_addr, size)?;
+                let vm_data = VmData::map_from_task(self.task, start_addr, size)?;
+                let data = CachedVmData {
+                    vm_data,
+                    last_use: self.use_counter,
+                };
                 // Replace everything between i and j with the new combined range.
                 self.data.splice(i..j, std::iter::once(data));
-                &self.data[i]
+                self.evict_over_budget(i)
             }
         };
-        Ok(vm_data)
+        let cached = &mut self.data[index];
+        cached.last_use = self.use_counter;
+        Ok(&cached.vm_data)
     }
 
     pub fn get_slice(&mut self, range: std::ops::Range<u64>) -> kernel_error::Result<&[u8]> {
@@ -699,6 +1224,49 @@ impl ForeignMemory {
         Ok(vm_data.get_slice(range))
     }
 
+    /// Reads a NUL-terminated string starting at `address`. The string is copied
+    /// into a scratch buffer one page at a time, so that short strings don't
+    /// cause reads past the page they end on. Unlike the other reads, this
+    /// doesn't go through the mapping cache, so nothing stays mapped afterwards.
+    /// Fails with `KernelError::InvalidValue` if there is no NUL within the first
+    /// `max_len` bytes.
+    pub fn read_c_string(&mut self, address: u64, max_len: usize) -> kernel_error::Result<String> {
+        let end_addr = address
+            .checked_add(max_len as u64)
+            .ok_or(KernelError::InvalidValue)?;
+        let mut bytes = Vec::new();
+        let mut chunk_buffer = vec![0u8; max_len.min(unsafe { vm_page_size })];
+        let mut chunk_start = address;
+        while chunk_start < end_addr {
+            if self.is_cancelled() {
+                return Err(KernelError::Cancelled);
+            }
+            let next_page_start = unsafe { mach_vm_trunc_page(chunk_start) + vm_page_size as u64 };
+            let mut size = next_page_start.min(end_addr) - chunk_start;
+            unsafe {
+                mach_vm_read_overwrite(
+                    self.task,
+                    chunk_start,
+                    size,
+                    chunk_buffer.as_mut_ptr() as mach_vm_address_t,
+                    &mut size,
+                )
+            }
+            .into_result()?;
+            if size == 0 {
+                return Err(KernelError::InvalidValue);
+            }
+            let chunk = &chunk_buffer[..size as usize];
+            if let Some(nul_pos) = chunk.iter().position(|b| *b == 0) {
+                bytes.extend_from_slice(&chunk[..nul_pos]);
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            bytes.extend_from_slice(chunk);
+            chunk_start += size;
+        }
+        Err(KernelError::InvalidValue)
+    }
+
     pub unsafe fn get_type_ref_at_address<T>(&mut self, address: u64) -> kernel_error::Result<&T> {
         let end_addr = address + mem::size_of::<T>() as u64;
         let vm_data = self.get_data_for_range(address..end_addr)?;
@@ -916,3 +1484,62 @@ where
     }
     modifications
 }
+
+#[cfg(test)]
+mod test {
+    use super::{find_image_for_address, DyldInfo, ImageSource, ModuleSvmaInfo, UnwindSectionInfo};
+
+    fn image(base_avma: u64, vmsize: u64) -> DyldInfo {
+        DyldInfo {
+            is_executable: false,
+            file: format!("/usr/lib/lib{base_avma:x}.dylib"),
+            base_avma,
+            vmsize,
+            module_info: ModuleSvmaInfo {
+                base_svma: 0,
+                text_svma: None,
+                stubs_svma: None,
+                stub_helper_svma: None,
+                got_svma: None,
+                eh_frame_svma: None,
+                eh_frame_hdr_svma: None,
+                text_segment_svma: None,
+            },
+            debug_id: None,
+            code_id: None,
+            arch: None,
+            unwind_sections: UnwindSectionInfo {
+                unwind_info_section: None,
+                eh_frame_section: None,
+                text_segment: None,
+            },
+            segments: Vec::new(),
+            source: ImageSource::OnDisk,
+        }
+    }
+
+    fn find(images: &[DyldInfo], addr: u64) -> Option<u64> {
+        find_image_for_address(images, addr).map(|image| image.base_avma)
+    }
+
+    #[test]
+    fn find_image_for_address_works() {
+        let images = vec![
+            image(0x1000, 0x1000),
+            image(0x3000, 0x2000),
+            image(u64::MAX - 0xff, u64::MAX),
+        ];
+        assert_eq!(find(&[], 0x1000), None);
+        assert_eq!(find(&images, 0xfff), None);
+        assert_eq!(find(&images, 0x1000), Some(0x1000));
+        assert_eq!(find(&images, 0x1fff), Some(0x1000));
+        // The end of an image is exclusive, and the gap after it belongs to no image.
+        assert_eq!(find(&images, 0x2000), None);
+        assert_eq!(find(&images, 0x2fff), None);
+        assert_eq!(find(&images, 0x3000), Some(0x3000));
+        assert_eq!(find(&images, 0x4fff), Some(0x3000));
+        assert_eq!(find(&images, 0x5000), None);
+        // A garbage vmsize must not overflow.
+        assert_eq!(find(&images, u64::MAX), Some(u64::MAX - 0xff));
+    }
+}
@@ -204,6 +204,16 @@ pub fn start_recording(
             eprintln!("Suggested remedy: You can profile any binaries that you've compiled yourself, or which are unsigned or locally-signed, such as anything installed by cargo install or by Homebrew.");
             std::process::exit(1)
         }
+        Err(e @ SamplingError::DyldInfoUnavailable(_)) => {
+            eprintln!("Profiling failed: {e}");
+            eprintln!();
+            eprintln!("samply needs to be allowed to inspect the target process. Please run 'samply setup' in order to grant appropriate entitlements, or run samply with sudo.");
+            std::process::exit(1)
+        }
+        Err(e @ SamplingError::UnsupportedDyldInfoFormat(_)) => {
+            eprintln!("Profiling failed: {e}");
+            std::process::exit(1)
+        }
         Err(e) => {
             eprintln!("An error occurred during profiling: {e}");
             std::process::exit(1)
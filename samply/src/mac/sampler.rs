@@ -103,8 +103,7 @@ impl Sampler {
             &mut profile,
             process_recycler.as_mut(),
             self.profile_creation_props.clone(),
-        )
-        .expect("couldn't create root TaskProfiler");
+        )?;
 
         let mut process_sample_datas = Vec::new();
         let mut stack_scratch_buffer = Vec::new();
@@ -29,8 +29,8 @@ use wholesym::samply_symbols;
 use super::error::SamplingError;
 use super::kernel_error::{IntoResult, KernelError};
 use super::proc_maps::{
-    proc_cmdline, DyldInfo, DyldInfoManager, Modification, ModuleSvmaInfo, StackwalkerRef,
-    VmSubData,
+    get_task_cpu_type, proc_cmdline, DyldInfo, DyldInfoManager, ImageSource, Modification,
+    ModuleSvmaInfo, StackwalkerRef, VmSubData,
 };
 use super::sampler::{ProcessSpecificPath, TaskInit};
 use super::thread_profiler::{get_thread_id, get_thread_name, ThreadProfiler};
@@ -137,9 +137,7 @@ impl TaskProfiler {
         let start_time = timestamp_converter.convert_time(start_time_mono);
 
         let mut lib_info_manager = DyldInfoManager::new(task);
-        let initial_lib_mods = lib_info_manager
-            .check_for_changes()
-            .map_err(|e| SamplingError::Ignorable("Could not check process libraries", e))?;
+        let initial_lib_mods = lib_info_manager.check_for_changes()?;
 
         let cmdline: Vec<String> = proc_cmdline(pid as i32).unwrap_or_default();
         let executable_name =
@@ -163,6 +161,16 @@ impl TaskProfiler {
             profile_creation_props.arg_count_to_include_in_process_name,
         );
 
+        if let Ok(cpu_type) = get_task_cpu_type(task) {
+            if !cpu_type.is_native() {
+                eprintln!(
+                    "Warning: {name} [pid: {pid}] runs as {}, but samply was built for {}. Its stacks may be incomplete.",
+                    cpu_type.arch().unwrap_or("an unknown architecture"),
+                    std::env::consts::ARCH
+                );
+            }
+        }
+
         let thread_acts = get_thread_list(task, profile_creation_props.main_thread_only)?;
         if thread_acts.is_empty() {
             return Err(SamplingError::Ignorable(
@@ -552,13 +560,15 @@ impl TaskProfiler {
         let unwind_info = unwind_info_data.map(UnwindSectionBytes::Remapped);
         let eh_frame = eh_frame_data.map(UnwindSectionBytes::Remapped);
         let text_segment = text_segment_data.map(UnwindSectionBytes::Remapped);
-        let debug_frame = if unwind_info.is_none() && eh_frame.is_none() {
-            // We have no unwind information.
-            // Let's try to open the file and use debug_frame.
-            get_debug_frame(&lib.file)
-        } else {
-            None
-        };
+        let debug_frame =
+            if unwind_info.is_none() && eh_frame.is_none() && lib.source == ImageSource::OnDisk {
+                // We have no unwind information.
+                // Let's try to open the file and use debug_frame. Shared cache images
+                // have no file of their own, so there's nothing to open for them.
+                get_debug_frame(&lib.file)
+            } else {
+                None
+            };
 
         let module = Module::new(
             lib.file.clone(),
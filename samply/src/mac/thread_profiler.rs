@@ -14,6 +14,7 @@ use super::thread_info::{
     thread_basic_info_data_t, thread_extended_info_data_t, thread_identifier_info_data_t,
     thread_info_t, time_value, THREAD_BASIC_INFO, THREAD_BASIC_INFO_COUNT, THREAD_EXTENDED_INFO,
     THREAD_EXTENDED_INFO_COUNT, THREAD_IDENTIFIER_INFO, THREAD_IDENTIFIER_INFO_COUNT,
+    TH_STATE_RUNNING,
 };
 use crate::mac::time;
 use crate::shared::recycling::ThreadRecycler;
@@ -259,10 +260,30 @@ pub fn get_thread_name(thread_act: thread_act_t) -> Result<Option<String>, Sampl
     Ok(if name.is_empty() { None } else { Some(name) })
 }
 
-// (user time, system time) in microseconds
-fn get_thread_cpu_time_since_thread_start(
-    thread_act: thread_act_t,
-) -> Result<(u64, u64), SamplingError> {
+/// The scheduler's view of a thread, from `thread_info(THREAD_BASIC_INFO)`.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadBasicInfo {
+    /// One of the `TH_STATE_*` values, e.g. running or waiting.
+    pub run_state: i32,
+    /// Recent CPU usage, scaled so that `TH_USAGE_SCALE` means 100%.
+    pub cpu_usage: i32,
+    /// Accumulated user time since thread start, in microseconds.
+    pub user_time_us: u64,
+    /// Accumulated system time since thread start, in microseconds.
+    pub system_time_us: u64,
+    /// Number of seconds the thread has been sleeping.
+    pub sleep_time: i32,
+}
+
+impl ThreadBasicInfo {
+    #[allow(unused)]
+    pub fn is_running(&self) -> bool {
+        self.run_state == TH_STATE_RUNNING as i32
+    }
+}
+
+pub fn get_thread_basic_info(thread_act: thread_act_t) -> Result<ThreadBasicInfo, SamplingError> {
     let mut basic_info_data: thread_basic_info_data_t = unsafe { mem::zeroed() };
     let mut count = THREAD_BASIC_INFO_COUNT;
     unsafe {
@@ -277,19 +298,27 @@ fn get_thread_cpu_time_since_thread_start(
     .map_err(|err| match err {
         KernelError::InvalidArgument
         | KernelError::MachSendInvalidDest
-        | KernelError::Terminated => SamplingError::ThreadTerminated(
-            "thread_info in get_thread_cpu_time_since_thread_start",
-            err,
-        ),
-        err => {
-            SamplingError::Ignorable("thread_info in get_thread_cpu_time_since_thread_start", err)
+        | KernelError::Terminated => {
+            SamplingError::ThreadTerminated("thread_info in get_thread_basic_info", err)
         }
+        err => SamplingError::Ignorable("thread_info in get_thread_basic_info", err),
     })?;
 
-    Ok((
-        time_value_to_microseconds(&basic_info_data.user_time),
-        time_value_to_microseconds(&basic_info_data.system_time),
-    ))
+    Ok(ThreadBasicInfo {
+        run_state: basic_info_data.run_state,
+        cpu_usage: basic_info_data.cpu_usage,
+        user_time_us: time_value_to_microseconds(&basic_info_data.user_time),
+        system_time_us: time_value_to_microseconds(&basic_info_data.system_time),
+        sleep_time: basic_info_data.sleep_time,
+    })
+}
+
+// (user time, system time) in microseconds
+fn get_thread_cpu_time_since_thread_start(
+    thread_act: thread_act_t,
+) -> Result<(u64, u64), SamplingError> {
+    let basic_info = get_thread_basic_info(thread_act)?;
+    Ok((basic_info.user_time_us, basic_info.system_time_us))
 }
 
 fn time_value_to_microseconds(tv: &time_value) -> u64 {